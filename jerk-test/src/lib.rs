@@ -3,14 +3,20 @@
 
 use jni_sys::*;
 use std::convert::*;
+use std::ffi::CString;
 use std::fmt::{self, Debug, Display, Formatter};
+use std::path::PathBuf;
 use std::ptr::null_mut;
+use std::sync::Mutex;
 
 pub type Result<T> = std::result::Result<T, JavaTestError>;
 
 #[derive(Clone)]
 pub enum JavaTestError {
     Unknown(String),
+    /// A Java exception escaped the test method.  Captured via `ExceptionOccurred`, cleared, and
+    /// then decoded through `getClass().getName()`, `getMessage()`, and `getStackTrace()`.
+    JavaException { class: String, message: Option<String>, stack_trace: Vec<String> },
     #[doc(hidden)] _NonExhaustive,
 }
 
@@ -18,6 +24,16 @@ impl Display for JavaTestError {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         match self {
             JavaTestError::Unknown(message) => write!(fmt, "{}", message),
+            JavaTestError::JavaException { class, message, stack_trace } => {
+                match message {
+                    Some(message) => writeln!(fmt, "{}: {}", class, message)?,
+                    None          => writeln!(fmt, "{}", class)?,
+                }
+                for line in stack_trace {
+                    writeln!(fmt, "\tat {}", line)?;
+                }
+                Ok(())
+            },
             JavaTestError::_NonExhaustive   => write!(fmt, "NonExhaustive"),
         }
     }
@@ -60,14 +76,199 @@ pub fn run_test(package: &str, class: &str, method: &str) -> Result<()> {
         let method_id   = (**env).GetStaticMethodID.unwrap()(env, class_id, method_id.as_ptr() as *const _, "()V\0".as_ptr() as *const _);
         assert_ne!(method_id, null_mut(), "Failed to GetStaticMethodID {}.{}", class, method);
         (**env).CallStaticVoidMethodA.unwrap()(env, class_id, method_id, [].as_ptr());
-        if (**env).ExceptionCheck.unwrap()(env) == JNI_TRUE {
-            (**env).ExceptionDescribe.unwrap()(env);
-            (**env).ExceptionClear.unwrap()(env);
-            Err(format!("{}.{}() threw a Java Exception", class, method).into())
-        } else {
-            Ok(())
+        check_exception(env)
+    }
+}
+
+/// Check for a pending Java exception, clearing it and decoding it into a `JavaTestError` if
+/// one is present.
+unsafe fn check_exception(env: *mut JNIEnv) -> Result<()> {
+    if (**env).ExceptionCheck.unwrap()(env) == JNI_TRUE {
+        let exception = (**env).ExceptionOccurred.unwrap()(env);
+        (**env).ExceptionClear.unwrap()(env);
+        let error = describe_exception(env, exception);
+        (**env).DeleteLocalRef.unwrap()(env, exception);
+        Err(error)
+    } else {
+        Ok(())
+    }
+}
+
+/// A typed argument to [`run_test_with`], lowered to a `jvalue` immediately before the call.
+pub enum JavaArg<'a> {
+    Boolean(jboolean),
+    Int(jint),
+    Long(jlong),
+    Double(jdouble),
+    Object(JObject),
+    Str(&'a str),
+}
+
+/// Minimal owned wrapper around a `jobject`, used to pass and return object arguments without
+/// pulling in the higher-level `jni` crate's reference-counted wrapper.
+#[derive(Copy, Clone)]
+pub struct JObject(pub jobject);
+
+/// The result of [`run_test_with`], decoded according to the method's declared return type.
+pub enum JavaValue {
+    Void,
+    Boolean(bool),
+    Int(jint),
+    Long(jlong),
+    Double(jdouble),
+    Object(JObject),
+}
+
+/// Execute a Java unit test method with arguments and/or a non-`void` return type.  Unlike
+/// [`run_test`], the caller supplies the JNI type signature (e.g. `"(II)I"`) so methods that
+/// take parameters or return a value - such as assertion helpers returning a boolean pass/fail -
+/// can be driven directly.
+pub fn run_test_with(package: &str, class: &str, method: &str, signature: &str, args: &[JavaArg]) -> Result<JavaValue> {
+    let env = test_thread_env();
+    if env == null_mut() { return Err("Couldn't initialize Java VM".into()); }
+
+    let class_id  = format!("{}/{}\0", package.replace(".", "/"), class);
+    let method_id = format!("{}\0", method);
+    let sig_id    = format!("{}\0", signature);
+
+    let return_type = signature.rsplit(')').next()
+        .and_then(|ret| ret.chars().next())
+        .ok_or_else(|| format!("Malformed JNI signature {:?} - missing return type", signature))?;
+    // Validated up front, before any local refs exist, so the match below never has to bail out
+    // of a `Str` argument's local ref mid-cleanup.
+    if !matches!(return_type, 'V' | 'Z' | 'I' | 'J' | 'D' | 'L' | '[') {
+        return Err(format!("Unsupported return type {:?} in signature {:?}", return_type, signature).into());
+    }
+
+    // Safety:
+    // * `**env` must be valid (non-null, not dangling, valid fn pointers if present)
+    // * string IDs must be `\0` terminated
+    unsafe {
+        let class_id  = (**env).FindClass.unwrap()(env, class_id.as_ptr() as *const _);
+        assert_ne!(class_id, null_mut(), "Failed to FindClass {}.{} - the corresponding .jar may not be loaded", package, class);
+        let method_id = (**env).GetStaticMethodID.unwrap()(env, class_id, method_id.as_ptr() as *const _, sig_id.as_ptr() as *const _);
+        assert_ne!(method_id, null_mut(), "Failed to GetStaticMethodID {}.{} with signature {}", class, method, signature);
+
+        let (values, created_strings) = args_to_jvalues(env, args)?;
+
+        let result = match return_type {
+            'V' => { (**env).CallStaticVoidMethodA.unwrap()(env, class_id, method_id, values.as_ptr()); JavaValue::Void },
+            'Z' => JavaValue::Boolean((**env).CallStaticBooleanMethodA.unwrap()(env, class_id, method_id, values.as_ptr()) == JNI_TRUE),
+            'I' => JavaValue::Int((**env).CallStaticIntMethodA.unwrap()(env, class_id, method_id, values.as_ptr())),
+            'J' => JavaValue::Long((**env).CallStaticLongMethodA.unwrap()(env, class_id, method_id, values.as_ptr())),
+            'D' => JavaValue::Double((**env).CallStaticDoubleMethodA.unwrap()(env, class_id, method_id, values.as_ptr())),
+            'L' | '[' => JavaValue::Object(JObject((**env).CallStaticObjectMethodA.unwrap()(env, class_id, method_id, values.as_ptr()))),
+            _ => unreachable!("return type was already validated above"),
+        };
+
+        for created_string in created_strings {
+            (**env).DeleteLocalRef.unwrap()(env, created_string as jobject);
         }
+
+        check_exception(env)?;
+
+        Ok(result)
+    }
+}
+
+/// Lower each [`JavaArg`] into a `jvalue`, creating a `jstring` for `JavaArg::Str` along the
+/// way.  The returned `jstring`s are local refs the caller must delete once the call completes.
+/// An embedded NUL byte in a `JavaArg::Str` is reported as a `JavaTestError` rather than a
+/// panic; any `jstring`s already created for earlier arguments are deleted before returning.
+unsafe fn args_to_jvalues(env: *mut JNIEnv, args: &[JavaArg]) -> Result<(Vec<jvalue>, Vec<jstring>)> {
+    let mut values = Vec::with_capacity(args.len());
+    let mut created_strings = Vec::new();
+    for arg in args {
+        let value = match arg {
+            JavaArg::Boolean(b) => jvalue { z: *b },
+            JavaArg::Int(i)     => jvalue { i: *i },
+            JavaArg::Long(l)    => jvalue { j: *l },
+            JavaArg::Double(d)  => jvalue { d: *d },
+            JavaArg::Object(o)  => jvalue { l: o.0 },
+            JavaArg::Str(s) => {
+                let cstr = match CString::new(*s) {
+                    Ok(cstr) => cstr,
+                    Err(_)   => {
+                        for created_string in created_strings {
+                            (**env).DeleteLocalRef.unwrap()(env, created_string as jobject);
+                        }
+                        return Err("JavaArg::Str must not contain an embedded NUL byte".into());
+                    },
+                };
+                let jstr  = (**env).NewStringUTF.unwrap()(env, cstr.as_ptr());
+                created_strings.push(jstr);
+                jvalue { l: jstr as jobject }
+            },
+        };
+        values.push(value);
+    }
+    Ok((values, created_strings))
+}
+
+/// Call a zero-argument, object-returning method, swallowing any *secondary* exception raised
+/// along the way so that introspecting one throwable can never panic or leave another pending.
+unsafe fn call_object_method(env: *mut JNIEnv, obj: jobject, class: jclass, name: &str, sig: &str) -> jobject {
+    let name_id = format!("{}\0", name);
+    let sig_id  = format!("{}\0", sig);
+    let method_id = (**env).GetMethodID.unwrap()(env, class, name_id.as_ptr() as *const _, sig_id.as_ptr() as *const _);
+    if method_id == null_mut() {
+        (**env).ExceptionClear.unwrap()(env);
+        return null_mut();
+    }
+    let result = (**env).CallObjectMethodA.unwrap()(env, obj, method_id, [].as_ptr());
+    if (**env).ExceptionCheck.unwrap()(env) == JNI_TRUE {
+        (**env).ExceptionClear.unwrap()(env);
+        return null_mut();
+    }
+    result
+}
+
+/// Convert a (possibly null) `jstring` into an owned `String`.
+unsafe fn jstring_to_string(env: *mut JNIEnv, string: jstring) -> Option<String> {
+    if string == null_mut() { return None; }
+    let chars = (**env).GetStringUTFChars.unwrap()(env, string, null_mut());
+    if chars == null_mut() { return None; }
+    let owned = std::ffi::CStr::from_ptr(chars).to_string_lossy().into_owned();
+    (**env).ReleaseStringUTFChars.unwrap()(env, string, chars);
+    Some(owned)
+}
+
+/// Decode a just-caught `jthrowable` into a structured [`JavaTestError::JavaException`], deleting
+/// every local ref it creates along the way.
+unsafe fn describe_exception(env: *mut JNIEnv, throwable: jthrowable) -> JavaTestError {
+    let throwable_class = (**env).GetObjectClass.unwrap()(env, throwable);
+    let class_class     = (**env).GetObjectClass.unwrap()(env, throwable_class as jobject);
+
+    let name_obj = call_object_method(env, throwable_class as jobject, class_class, "getName", "()Ljava/lang/String;");
+    let class = jstring_to_string(env, name_obj as jstring).unwrap_or_else(|| "<unknown>".to_string());
+    (**env).DeleteLocalRef.unwrap()(env, name_obj);
+    (**env).DeleteLocalRef.unwrap()(env, class_class as jobject);
+
+    let message_obj = call_object_method(env, throwable, throwable_class, "getMessage", "()Ljava/lang/String;");
+    let message = jstring_to_string(env, message_obj as jstring);
+    (**env).DeleteLocalRef.unwrap()(env, message_obj);
+
+    let mut stack_trace = Vec::new();
+    let frames = call_object_method(env, throwable, throwable_class, "getStackTrace", "()[Ljava/lang/StackTraceElement;") as jobjectArray;
+    if frames != null_mut() {
+        let len = (**env).GetArrayLength.unwrap()(env, frames);
+        for i in 0..len {
+            let frame = (**env).GetObjectArrayElement.unwrap()(env, frames, i);
+            if frame == null_mut() { continue; }
+            let frame_class = (**env).GetObjectClass.unwrap()(env, frame);
+            let string_obj  = call_object_method(env, frame, frame_class, "toString", "()Ljava/lang/String;");
+            if let Some(line) = jstring_to_string(env, string_obj as jstring) {
+                stack_trace.push(line);
+            }
+            (**env).DeleteLocalRef.unwrap()(env, string_obj);
+            (**env).DeleteLocalRef.unwrap()(env, frame_class as jobject);
+            (**env).DeleteLocalRef.unwrap()(env, frame);
+        }
+        (**env).DeleteLocalRef.unwrap()(env, frames as jobject);
     }
+    (**env).DeleteLocalRef.unwrap()(env, throwable_class as jobject);
+
+    JavaTestError::JavaException { class, message, stack_trace }
 }
 
 
@@ -77,35 +278,89 @@ pub fn test_vm() -> *mut JavaVM { **VM }
 lazy_static::lazy_static! { static ref VM : ThreadSafe<*mut JavaVM> = ThreadSafe(create_java_vm()); }
 
 /// Get a handle to the Java environment for the current thread, attaching if one doesn't already exist.
-pub fn test_thread_env() -> *mut JNIEnv { ENV.with(|e| *e) }
-thread_local! { static ENV : *mut JNIEnv = attach_current_thread(); }
+/// The attachment is held for the lifetime of the thread - see [`AttachGuard`].
+pub fn test_thread_env() -> *mut JNIEnv { ATTACHMENT.with(|guard| guard.env) }
+thread_local! { static ATTACHMENT : AttachGuard = attach_current_thread(); }
+
+/// RAII guard for a thread's attachment to the JVM.  Calls `DetachCurrentThread` on `Drop`, but
+/// only if this guard is the one that attached the thread - a thread that was already attached
+/// (e.g. re-entrant calls from Java) is left alone, since detaching it here would pull the rug
+/// out from under whoever attached it first.
+pub struct AttachGuard {
+    env:            *mut JNIEnv,
+    newly_attached: bool,
+}
+
+impl AttachGuard {
+    pub fn env(&self) -> *mut JNIEnv { self.env }
+}
+
+impl Drop for AttachGuard {
+    fn drop(&mut self) {
+        if self.newly_attached {
+            let vm = test_vm();
+            unsafe { (**vm).DetachCurrentThread.unwrap()(vm); }
+        }
+    }
+}
 
-fn attach_current_thread() -> *mut JNIEnv {
+/// Attach the current thread to the JVM, returning a guard that detaches it on `Drop` - but
+/// only if this call is the one that attached it.
+pub fn attach_current_thread() -> AttachGuard { attach(false) }
+
+/// Attach the current thread to the JVM as a daemon thread, so it doesn't block VM shutdown.
+/// Use this for worker threads that run tests but shouldn't keep the process alive on their own.
+pub fn attach_current_thread_as_daemon() -> AttachGuard { attach(true) }
+
+fn attach(as_daemon: bool) -> AttachGuard {
     let vm = test_vm();
+
+    // Safety: `**vm` must be valid.  `GetEnv` reports whether this thread is already attached
+    // without attaching it itself, so we can tell apart "already attached" from "newly attached"
+    // and only detach the threads we ourselves attached.
     let mut env = null_mut();
-    assert_eq!(JNI_OK, unsafe { (**vm).AttachCurrentThread.unwrap()(vm, &mut env, null_mut()) });
-    env as *mut _
+    if unsafe { (**vm).GetEnv.unwrap()(vm, &mut env, JNI_VERSION_1_6) } == JNI_OK {
+        return AttachGuard { env: env as *mut _, newly_attached: false };
+    }
+
+    let mut env = null_mut();
+    let result = unsafe {
+        if as_daemon {
+            (**vm).AttachCurrentThreadAsDaemon.unwrap()(vm, &mut env, null_mut())
+        } else {
+            (**vm).AttachCurrentThread.unwrap()(vm, &mut env, null_mut())
+        }
+    };
+    assert_eq!(JNI_OK, result);
+
+    AttachGuard { env: env as *mut _, newly_attached: true }
 }
 
 fn create_java_vm() -> *mut JavaVM {
+    // A process may only ever create one JVM.  If the host application (or another test
+    // harness) already created one, reuse it instead of failing - this is what lets `jerk`
+    // be embedded inside bigger applications rather than owning the whole process.
+    let mut existing_vm : *mut JavaVM = null_mut();
+    let mut num_vms = 0;
+    assert_eq!(JNI_OK, unsafe { JNI_GetCreatedJavaVMs(&mut existing_vm, 1, &mut num_vms) });
+    if num_vms > 0 {
+        return existing_vm;
+    }
+
+    let config = JVM_CONFIG.lock().unwrap().clone().unwrap_or_else(JvmConfig::implicit_default);
+
     let mut vm  = 0 as *mut _;
     let mut env = 0 as *mut _;
 
-    let classpath = format!("-Djava.class.path={}\0", std::env::var("CLASSPATH").unwrap());
-
-    let mut options = [
-        //JavaVMOption { optionString: "-verbose:class\0".as_ptr() as *const _ as *mut _, extraInfo: null_mut() },
-        //JavaVMOption { optionString: "-verbose:jni\0".as_ptr() as *const _ as *mut _, extraInfo: null_mut() },
-        JavaVMOption { optionString: "-ea\0".as_ptr() as *const _ as *mut _, extraInfo: null_mut() }, // Enable Assertions
-        JavaVMOption { optionString: "-esa\0".as_ptr() as *const _ as *mut _, extraInfo: null_mut() }, // Enable System Assertions
-        JavaVMOption { optionString: classpath.as_ptr() as *const _ as *mut _, extraInfo: null_mut() },
-    ];
+    let mut options : Vec<_> = config.options.iter()
+        .map(|option| JavaVMOption { optionString: option.as_ptr() as *mut _, extraInfo: null_mut() })
+        .collect();
 
     let mut args = JavaVMInitArgs {
-        version:            JNI_VERSION_1_6,
+        version:            config.version.as_jint(),
         nOptions:           options.len() as _,
         options:            options.as_mut_ptr(),
-        ignoreUnrecognized: JNI_FALSE,
+        ignoreUnrecognized: if config.ignore_unrecognized { JNI_TRUE } else { JNI_FALSE },
     };
 
     assert_eq!(JNI_OK, unsafe { JNI_GetDefaultJavaVMInitArgs(&mut args as *mut _ as *mut _) });
@@ -114,6 +369,152 @@ fn create_java_vm() -> *mut JavaVM {
     vm
 }
 
+/// JNI version to request when creating the VM.  Mirrors the subset of `JNI_VERSION_*`
+/// constants a test harness would plausibly want to target.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum JNIVersion {
+    V1_1,
+    V1_2,
+    V1_4,
+    V1_6,
+    V1_8,
+    V9,
+    V10,
+}
+
+impl JNIVersion {
+    fn as_jint(self) -> jint {
+        match self {
+            JNIVersion::V1_1 => JNI_VERSION_1_1,
+            JNIVersion::V1_2 => JNI_VERSION_1_2,
+            JNIVersion::V1_4 => JNI_VERSION_1_4,
+            JNIVersion::V1_6 => JNI_VERSION_1_6,
+            JNIVersion::V1_8 => JNI_VERSION_1_8,
+            JNIVersion::V9   => JNI_VERSION_9,
+            JNIVersion::V10  => JNI_VERSION_10,
+        }
+    }
+}
+
+/// Fully-validated JVM configuration, ready to be turned into a `JavaVMInitArgs`.  `None` in
+/// [`JVM_CONFIG`] means "nobody has configured a [`JvmBuilder`] yet", in which case
+/// [`JvmConfig::implicit_default`] reproduces `jerk`'s original hardcoded behavior.
+#[derive(Clone)]
+struct JvmConfig {
+    version:             JNIVersion,
+    options:             Vec<CString>,
+    ignore_unrecognized: bool,
+}
+
+impl JvmConfig {
+    fn implicit_default() -> Self {
+        let classpath = format!("-Djava.class.path={}", std::env::var("CLASSPATH").unwrap());
+        JvmConfig {
+            version: JNIVersion::V1_6,
+            options: vec![
+                CString::new("-ea").unwrap(),  // Enable Assertions
+                CString::new("-esa").unwrap(), // Enable System Assertions
+                CString::new(classpath).unwrap(),
+            ],
+            ignore_unrecognized: false,
+        }
+    }
+}
+
+lazy_static::lazy_static! { static ref JVM_CONFIG : Mutex<Option<JvmConfig>> = Mutex::new(None); }
+
+/// Builder for the options passed to [`JNI_CreateJavaVM`] when `jerk` ends up creating the VM
+/// itself (as opposed to attaching to one that already exists), modeled on the
+/// `InitArgsBuilder` pattern from the `jni` crate.
+///
+/// Configure it and call [`JvmBuilder::build_and_run_test`] *before* `test_vm()` /
+/// `test_thread_env()` / `run_test()` are used for the first time - the global VM is created
+/// lazily from this configuration on first access, and only once per process.
+#[derive(Clone)]
+pub struct JvmBuilder {
+    version:             JNIVersion,
+    options:             Vec<String>,
+    classpath:           Vec<PathBuf>,
+    ignore_unrecognized: bool,
+}
+
+impl Default for JvmBuilder {
+    fn default() -> Self {
+        JvmBuilder {
+            version:             JNIVersion::V1_6,
+            options:             vec!["-ea".to_string(), "-esa".to_string()],
+            classpath:           Vec::new(),
+            ignore_unrecognized: false,
+        }
+    }
+}
+
+impl JvmBuilder {
+    pub fn new() -> Self { Self::default() }
+
+    /// Request a specific JNI version instead of the default `V1_6`.
+    pub fn version(mut self, version: JNIVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Add a raw JVM option, e.g. `-Xcheck:jni` or a `-Xmx...` heap flag.
+    pub fn option(mut self, option: &str) -> Self {
+        self.options.push(option.to_string());
+        self
+    }
+
+    /// Set the classpath programmatically instead of relying on the `CLASSPATH` env var.
+    /// Replaces any classpath set by a previous call.
+    pub fn classpath(mut self, classpath: &[PathBuf]) -> Self {
+        self.classpath = classpath.to_vec();
+        self
+    }
+
+    /// Whether the VM should tolerate options it doesn't recognize instead of failing to start.
+    pub fn ignore_unrecognized(mut self, ignore: bool) -> Self {
+        self.ignore_unrecognized = ignore;
+        self
+    }
+
+    fn into_config(self) -> Result<JvmConfig> {
+        let mut options = Vec::with_capacity(self.options.len() + 1);
+        for option in self.options {
+            let option = CString::new(option.clone())
+                .map_err(|_| format!("JVM option {:?} contains an embedded NUL byte", option))?;
+            options.push(option);
+        }
+        // Mirror `JvmConfig::implicit_default`'s `CLASSPATH` fallback: a caller who only reaches
+        // for the builder to tweak the version or add an option (without calling `.classpath()`)
+        // should still get the classpath their env var was already providing, rather than a VM
+        // silently started with no classpath at all.
+        let classpath = if !self.classpath.is_empty() {
+            let joined = std::env::join_paths(&self.classpath)
+                .map_err(|e| format!("invalid classpath {:?}: {}", self.classpath, e))?;
+            Some(joined.to_string_lossy().into_owned())
+        } else {
+            std::env::var("CLASSPATH").ok()
+        };
+        match classpath {
+            Some(classpath) => {
+                let classpath = format!("-Djava.class.path={}", classpath);
+                options.push(CString::new(classpath).map_err(|_| "classpath contains an embedded NUL byte")?);
+            },
+            None => return Err("no classpath configured - call JvmBuilder::classpath(...) or set the CLASSPATH env var".into()),
+        }
+        Ok(JvmConfig { version: self.version, options, ignore_unrecognized: self.ignore_unrecognized })
+    }
+
+    /// Validate and install this configuration as the default used the first time the global
+    /// VM is created, then run `body`.  Option-validation failures (e.g. an embedded NUL byte)
+    /// are returned as a `JavaTestError` rather than panicking.
+    pub fn build_and_run_test<R>(self, body: impl FnOnce() -> Result<R>) -> Result<R> {
+        let config = self.into_config()?;
+        *JVM_CONFIG.lock().unwrap() = Some(config);
+        body()
+    }
+}
+
 struct ThreadSafe<T>(pub T);
 impl<T> std::ops::Deref for ThreadSafe<T> { type Target = T; fn deref(&self) -> &Self::Target { &self.0 } }
 unsafe impl<T> Send for ThreadSafe<T> {}